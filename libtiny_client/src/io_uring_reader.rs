@@ -0,0 +1,275 @@
+//! High-throughput ingestion path for server sockets, using an io_uring *provided buffer ring*
+//! instead of a per-`recv` buffer.
+//!
+//! A ring of fixed-size buffers is preallocated up front and registered with the kernel under a
+//! buffer-group id. Each submitted `recv` lets the kernel pick one of those buffers instead of us
+//! handing it one, and the completion tells us which buffer id was used and how many bytes
+//! landed in it. When a `recv` completes without a line split across it and the previous one,
+//! lines are parsed directly out of the kernel-provided buffer with no extra allocation or copy;
+//! only a trailing partial line is copied out, to be stitched onto the front of the next `recv`.
+//!
+//! One `UringReader` drives a single `fd` at a time -- `recv_lines` submits and waits for exactly
+//! one completion per call, so there's no cross-connection multiplexing to get wrong. Running
+//! several connections concurrently means one `UringReader` per connection (each with its own
+//! ring), same as one `Pinger` per connection.
+//!
+//! Callers fall back to the existing epoll-based read path at startup when io_uring isn't
+//! available on the host (`UringReader::new` returns `None`), or when built without the
+//! `io-uring` feature.
+//!
+//! Gated behind the `io-uring` Cargo feature, which (Cargo.toml isn't part of this checkout)
+//! needs:
+//! ```toml
+//! [features]
+//! io-uring = ["dep:io-uring", "dep:libc"]
+//! [dependencies]
+//! io-uring = { version = "0.6", optional = true }
+//! libc = { version = "0.2", optional = true }
+//! ```
+
+#![cfg(feature = "io-uring")]
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+use io_uring::squeue::Flags as SqFlags;
+use io_uring::types::BufRingEntry;
+use io_uring::{cqueue, opcode, types, IoUring};
+
+use crate::pinger::Pinger;
+
+/// Buffer-group id this reader registers its provided buffers under. Each `UringReader` has its
+/// own io_uring instance with a single buffer group in it, so one fixed id is enough.
+const BUF_GROUP: u16 = 0;
+
+/// Size of each buffer in the ring. IRC lines are bounded (512 bytes on the wire plus tags), so a
+/// generous read chunk is enough to make partial reads rare.
+const BUF_SIZE: usize = 4096;
+
+/// Number of buffers kept in the ring. Must be a power of two: the kernel indexes the ring with
+/// `NUM_BUFS - 1` as a mask.
+const NUM_BUFS: u16 = 256;
+
+/// Owns the provided-buffer-ring backing storage (the data buffers, and the ring of
+/// addr/len/bid descriptors registered with the kernel via `Submitter::register_buf_ring`) plus
+/// the `io_uring` instance submitting `recv`s against it.
+///
+/// Also carries a small byte buffer for a line that was split across two `recv`s, since IRC lines
+/// aren't guaranteed to land inside a single `BUF_SIZE` chunk.
+pub(crate) struct UringReader {
+    ring: IoUring,
+    bufs: Vec<Box<[u8]>>,
+    /// The registered ring of buffer descriptors. Must not move after `register_buf_ring`, since
+    /// the kernel was given its address.
+    entries: Box<[BufRingEntry]>,
+    tail: u16,
+    carry: Vec<u8>,
+}
+
+/// A buffer leased out of the ring for one completed `recv`. Dropping this guard returns the
+/// buffer to the ring, so it must not be dropped before the parser is done with the bytes, and
+/// the bytes must never be read again afterwards.
+struct RecvBuf<'a> {
+    reader: &'a mut UringReader,
+    bid: u16,
+    len: usize,
+}
+
+impl<'a> RecvBuf<'a> {
+    fn bytes(&self) -> &[u8] {
+        &self.reader.bufs[self.bid as usize][..self.len]
+    }
+}
+
+impl<'a> Drop for RecvBuf<'a> {
+    fn drop(&mut self) {
+        self.reader.release_buf(self.bid);
+    }
+}
+
+impl UringReader {
+    /// Tries to set up an io_uring instance with a registered provided buffer ring. Returns
+    /// `None` (rather than an error) when io_uring isn't usable on this host, so the caller can
+    /// fall back to the epoll-based read path transparently.
+    pub(crate) fn new() -> Option<UringReader> {
+        let ring = IoUring::new(128).ok()?;
+        let bufs: Vec<Box<[u8]>> = (0..NUM_BUFS)
+            .map(|_| vec![0u8; BUF_SIZE].into_boxed_slice())
+            .collect();
+        let entries: Box<[BufRingEntry]> = vec![BufRingEntry::default(); NUM_BUFS as usize].into_boxed_slice();
+
+        let mut reader = UringReader {
+            ring,
+            bufs,
+            entries,
+            tail: 0,
+            carry: Vec::new(),
+        };
+
+        let ring_addr = reader.entries.as_ptr() as u64;
+        unsafe {
+            reader
+                .ring
+                .submitter()
+                .register_buf_ring(ring_addr, NUM_BUFS, BUF_GROUP)
+                .ok()?;
+        }
+
+        for bid in 0..NUM_BUFS {
+            reader.release_buf(bid);
+        }
+
+        Some(reader)
+    }
+
+    /// Returns buffer `bid` to the ring at the current tail, making it available for the kernel
+    /// to hand out to a future `recv` again.
+    fn release_buf(&mut self, bid: u16) {
+        let mask = NUM_BUFS - 1;
+        let idx = (self.tail & mask) as usize;
+        let entry = &mut self.entries[idx];
+        entry.set_addr(self.bufs[bid as usize].as_ptr() as u64);
+        entry.set_len(BUF_SIZE as u32);
+        entry.set_bid(bid);
+        self.tail = self.tail.wrapping_add(1);
+        // The kernel reads `tail` from the last-indexed entry; `register_buf_ring`/the crate's
+        // `BufRingEntry` helpers take care of publishing it with the right memory ordering.
+        BufRingEntry::set_tail(&mut self.entries, self.tail);
+    }
+
+    /// Submits a provided-buffer `recv` for `fd`, tagging the completion with `fd` as a sanity
+    /// check: `recv_lines` verifies the completion it pops back is for the `fd` it submitted.
+    fn submit_recv(&mut self, fd: RawFd) -> io::Result<()> {
+        let recv_e = opcode::Recv::new(types::Fd(fd), std::ptr::null_mut(), BUF_SIZE as u32)
+            .buf_group(BUF_GROUP)
+            .build()
+            .flags(SqFlags::BUFFER_SELECT)
+            .user_data(fd as u64);
+        unsafe {
+            self.ring
+                .submission()
+                .push(&recv_e)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "submission queue full"))?;
+        }
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    /// Runs one `recv` for `fd` to completion, parses complete IRC lines out of the bytes the
+    /// kernel delivered, calls `pinger.reset()` once per line (any inbound traffic counts as a
+    /// sign of life), and hands each line to `on_line`.
+    ///
+    /// On ring exhaustion (the kernel had no buffer left to hand back) this retries the `recv`
+    /// rather than failing the read. It does not replenish the ring itself: every buffer leased by
+    /// an earlier call was already returned to the ring when its `RecvBuf` was dropped.
+    pub(crate) fn recv_lines(
+        &mut self,
+        fd: RawFd,
+        pinger: &mut Pinger,
+        mut on_line: impl FnMut(&[u8]),
+    ) -> io::Result<()> {
+        loop {
+            self.submit_recv(fd)?;
+
+            let cqe = loop {
+                if let Some(cqe) = self.ring.completion().next() {
+                    break cqe;
+                }
+                self.ring.submit_and_wait(1)?;
+            };
+
+            if cqe.user_data() != fd as u64 {
+                // Only one `recv` is ever in flight per `UringReader`, so this would mean the
+                // ring handed back a completion for a submission we didn't just make.
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "io_uring completion did not match the submitted fd",
+                ));
+            }
+
+            let result = cqe.result();
+
+            if result == -libc::ENOBUFS {
+                // The kernel had nothing to hand us: the buffers we already hold were released
+                // back into the ring by previous `RecvBuf` drops, so just retry the recv.
+                continue;
+            }
+
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+
+            if result == 0 {
+                // The peer closed the connection. A 0-byte provided-buffer completion may not
+                // carry a buffer id at all (nothing was read into one), so this must be handled
+                // before `cqueue::buffer_select` -- otherwise a disconnect either panics (no
+                // buffer id) or, if a buffer id happens to be present, returns with no lines and
+                // the caller busy-loops `recv_lines` against a dead socket forever.
+                if let Some(bid) = cqueue::buffer_select(cqe.flags()) {
+                    self.release_buf(bid);
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "peer closed the connection",
+                ));
+            }
+
+            let len = result as usize;
+            let bid = cqueue::buffer_select(cqe.flags())
+                .expect("provided-buffer recv completed without a buffer id");
+
+            // Safety: `bid` was just handed back by the kernel for this completion, and hasn't
+            // been recycled (it's removed from the ring until `RecvBuf` is dropped), so it's
+            // still exclusively ours for the duration of this borrow.
+            let recv_buf = RecvBuf {
+                reader: &mut *self,
+                bid,
+                len,
+            };
+
+            if recv_buf.reader.carry.is_empty() {
+                // Common case: no line was left split across the previous `recv`, so parse
+                // straight out of the kernel-provided buffer instead of copying it into `carry`
+                // first.
+                let mut rest = recv_buf.bytes();
+                while let Some(pos) = rest.iter().position(|&b| b == b'\n') {
+                    let (line, after) = rest.split_at(pos);
+                    let line = if line.ends_with(b"\r") {
+                        &line[..line.len() - 1]
+                    } else {
+                        line
+                    };
+                    pinger.reset();
+                    on_line(line);
+                    rest = &after[1..];
+                }
+                let leftover = rest.to_vec();
+                drop(recv_buf);
+                self.carry = leftover;
+            } else {
+                // A previous `recv` ended mid-line: stitch the carried-over partial line onto
+                // the front of this buffer before parsing. This is the only path that copies the
+                // whole buffer.
+                let mut carry = std::mem::take(&mut recv_buf.reader.carry);
+                carry.extend_from_slice(recv_buf.bytes());
+                drop(recv_buf);
+
+                let mut rest = &carry[..];
+                while let Some(pos) = rest.iter().position(|&b| b == b'\n') {
+                    let (line, after) = rest.split_at(pos);
+                    let line = if line.ends_with(b"\r") {
+                        &line[..line.len() - 1]
+                    } else {
+                        line
+                    };
+                    pinger.reset();
+                    on_line(line);
+                    rest = &after[1..];
+                }
+                self.carry = rest.to_vec();
+            }
+
+            return Ok(());
+        }
+    }
+}
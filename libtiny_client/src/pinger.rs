@@ -1,14 +1,27 @@
-//! Implements two-state "pinger" task that drives sending pings to the server to check liveness of
-//! the connection.
+//! Implements a "pinger" task that drives sending pings to the server to check liveness of the
+//! connection. The task has two states:
+//!
+//! - `Idle`: waiting for `ping_interval` to elapse since the last sign of life from the server.
+//! - `AwaitingPong`: a ping was just sent, waiting for either a `pong()` call (the server replied
+//!   with a `PONG`) or `pong_timeout` to elapse (the server is probably dead).
 
 use futures::FutureExt;
 use futures::{pin_mut, select, stream::StreamExt};
+use rand::Rng;
 use std::time::Duration;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio::timer::delay_for;
 
 pub(crate) struct Pinger {
-    snd_rst: mpsc::Sender<()>,
+    snd_cmd: mpsc::Sender<Cmd>,
+    snd_pong: mpsc::Sender<()>,
+}
+
+enum Cmd {
+    /// Any line was received from the server: re-arm the idle timer.
+    Reset,
+    /// Stop the task. The `oneshot::Sender` is fired once the task has fully exited.
+    Shutdown(oneshot::Sender<()>),
 }
 
 #[derive(Debug)]
@@ -18,52 +31,144 @@ pub(crate) enum Event {
 }
 
 enum PingerState {
-    /// Signal a "ping" on timeout. State moves to `ExpectPong`.
-    SendPing,
-    /// Signal a "disconnect" on timeout.
-    ExpectPong,
+    /// Signal a "ping" on timeout. State moves to `AwaitingPong`.
+    Idle,
+    /// Signal a "disconnect" on timeout. Only an explicit `pong()` moves back to `Idle`.
+    AwaitingPong,
+}
+
+/// Builds a [`Pinger`] with a configurable ping interval and pong timeout.
+pub(crate) struct PingerBuilder {
+    ping_interval: Duration,
+    pong_timeout: Duration,
+}
+
+impl PingerBuilder {
+    fn new() -> PingerBuilder {
+        PingerBuilder {
+            ping_interval: Duration::from_secs(30),
+            pong_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// How long to wait, after the last sign of life from the server, before sending a ping.
+    pub(crate) fn ping_interval(mut self, ping_interval: Duration) -> PingerBuilder {
+        self.ping_interval = ping_interval;
+        self
+    }
+
+    /// How long to wait for a `PONG` after a ping was sent before giving up on the connection.
+    pub(crate) fn pong_timeout(mut self, pong_timeout: Duration) -> PingerBuilder {
+        self.pong_timeout = pong_timeout;
+        self
+    }
+
+    pub(crate) fn spawn(self) -> (Pinger, mpsc::Receiver<Event>) {
+        let (snd_ev, rcv_ev) = mpsc::channel(1);
+        // No need for sending another "reset" when there's already one waiting to be processed
+        let (snd_cmd, rcv_cmd) = mpsc::channel(1);
+        let (snd_pong, rcv_pong) = mpsc::channel(1);
+        tokio::runtime::current_thread::spawn(pinger_task(
+            self.ping_interval,
+            self.pong_timeout,
+            rcv_cmd,
+            rcv_pong,
+            snd_ev,
+        ));
+        (Pinger { snd_cmd, snd_pong }, rcv_ev)
+    }
+}
+
+/// Adds up to 10% random jitter to `interval`, to avoid many connections pinging their servers at
+/// the same time.
+fn jitter(interval: Duration) -> Duration {
+    let factor = rand::thread_rng().gen_range(0.9, 1.1);
+    interval.mul_f64(factor)
+}
+
+/// Sends a `SendPing`, but never blocks: if the channel (capacity 1) is already full we drop the
+/// new one rather than panic. A full channel means the consumer hasn't caught up with the
+/// previous ping yet, so a duplicate `SendPing` can be dropped cleanly. Unlike `Disconnect`, a
+/// dropped ping isn't fatal -- there'll be another one along in `ping_interval`.
+fn send_ping(snd_ev: &mut mpsc::Sender<Event>, ev: Event) {
+    let _ = snd_ev.try_send(ev);
 }
 
-async fn pinger_task(rcv_rst: mpsc::Receiver<()>, mut snd_ev: mpsc::Sender<Event>) {
-    let mut rcv_rst_fused = rcv_rst.fuse();
-    let mut state = PingerState::SendPing;
-    loop {
+async fn pinger_task(
+    ping_interval: Duration,
+    pong_timeout: Duration,
+    rcv_cmd: mpsc::Receiver<Cmd>,
+    rcv_pong: mpsc::Receiver<()>,
+    mut snd_ev: mpsc::Sender<Event>,
+) {
+    let mut rcv_cmd_fused = rcv_cmd.fuse();
+    let mut rcv_pong_fused = rcv_pong.fuse();
+    let mut state = PingerState::Idle;
+
+    'outer: loop {
+        let timeout = match state {
+            PingerState::Idle => jitter(ping_interval),
+            PingerState::AwaitingPong => pong_timeout,
+        };
+
         // NOTE: The code about does not work:
-        // let mut delay = delay_for(Duration::from_secs(30));
+        // let mut delay = delay_for(timeout);
         // Instead I need this weird code below. Not sure if this is a bug or not.
         let delay = async {
-            delay_for(Duration::from_secs(30)).await;
+            delay_for(timeout).await;
         }
             .fuse();
         pin_mut!(delay);
 
-        eprintln!("pinger: select");
-        select! {
-            () = delay => {
-                eprintln!("pinger: delay yielded");
-                match state {
-                    PingerState::SendPing => {
-                        state = PingerState::ExpectPong;
-                        eprintln!("pinger: SendPing");
-                        snd_ev.try_send(Event::SendPing).unwrap();
-                    }
-                    PingerState::ExpectPong => {
-                        eprintln!("pinger: Disconnect");
-                        snd_ev.try_send(Event::Disconnect).unwrap();
-                        return;
+        // Re-select on the same timer until we actually need to re-arm it. In particular, a
+        // `reset()` while `AwaitingPong` must not restart the pong timeout: we're not looking
+        // for "any traffic" any more, we're looking for an actual `PONG`.
+        loop {
+            select! {
+                () = delay => {
+                    match state {
+                        PingerState::Idle => {
+                            state = PingerState::AwaitingPong;
+                            send_ping(&mut snd_ev, Event::SendPing);
+                        }
+                        PingerState::AwaitingPong => {
+                            // Unlike `SendPing`, this must not be dropped: it's the one and
+                            // only notification that the connection is dead, and the exact
+                            // message-flood scenario that fills the channel is when callers
+                            // most need to hear about it. Block until there's room.
+                            let _ = snd_ev.send(Event::Disconnect).await;
+                            return;
+                        }
                     }
+                    continue 'outer;
                 }
-            }
-            cmd = rcv_rst_fused.next() => {
-                eprintln!("pinger: rcv_rst yielded");
-                match cmd {
-                    None => {
-                        eprintln!("pinger: Return");
-                        return;
+                cmd = rcv_cmd_fused.next() => {
+                    match cmd {
+                        None => {
+                            return;
+                        }
+                        Some(Cmd::Shutdown(done)) => {
+                            let _ = done.send(());
+                            return;
+                        }
+                        Some(Cmd::Reset) => {
+                            if let PingerState::Idle = state {
+                                // Re-arm the idle timer.
+                                continue 'outer;
+                            }
+                            // Unrelated traffic while awaiting a pong: keep waiting.
+                        }
                     }
-                    Some(()) => {
-                        eprintln!("pinger: Reset");
-                        state = PingerState::SendPing;
+                }
+                cmd = rcv_pong_fused.next() => {
+                    match cmd {
+                        None => {
+                            return;
+                        }
+                        Some(()) => {
+                            state = PingerState::Idle;
+                            continue 'outer;
+                        }
                     }
                 }
             }
@@ -72,17 +177,38 @@ async fn pinger_task(rcv_rst: mpsc::Receiver<()>, mut snd_ev: mpsc::Sender<Event
 }
 
 impl Pinger {
+    pub(crate) fn builder() -> PingerBuilder {
+        PingerBuilder::new()
+    }
+
     pub(crate) fn new() -> (Pinger, mpsc::Receiver<Event>) {
-        let (snd_ev, rcv_ev) = mpsc::channel(1);
-        // No need for sending another "reset" when there's already one waiting to be processed
-        let (snd_rst, rcv_rst) = mpsc::channel(1);
-        tokio::runtime::current_thread::spawn(pinger_task(rcv_rst, snd_ev));
-        (Pinger { snd_rst }, rcv_ev)
+        Pinger::builder().spawn()
     }
 
+    /// Call when any line is received from the server. Does not clear an outstanding
+    /// `AwaitingPong` expectation -- only `pong()` does that.
     pub(crate) fn reset(&mut self) {
         // Ignore errors: no need to send another "reset" when there's already one waiting to be
         // processed
-        let _ = self.snd_rst.try_send(());
+        let _ = self.snd_cmd.try_send(Cmd::Reset);
+    }
+
+    /// Call when a `PONG` is received from the server.
+    pub(crate) fn pong(&mut self) {
+        let _ = self.snd_pong.try_send(());
     }
-}
\ No newline at end of file
+
+    /// Stop the pinger task. Returns a future that resolves once `pinger_task` has fully exited,
+    /// so callers (e.g. a reconnection routine) can tear down the old task deterministically
+    /// before spawning a new one.
+    pub(crate) fn shutdown(&mut self) -> impl std::future::Future<Output = ()> {
+        let (snd_done, rcv_done) = oneshot::channel();
+        // Unlike `reset()`, a shutdown must not be dropped: if the channel is full we wait for
+        // room rather than silently giving up.
+        let mut snd_cmd = self.snd_cmd.clone();
+        async move {
+            let _ = snd_cmd.send(Cmd::Shutdown(snd_done)).await;
+            let _ = rcv_done.await;
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! A central registry of per-server [`Pinger`]s.
+//!
+//! `Pinger::new`/`Pinger::builder` spawn one task per connection with no aggregate view; a client
+//! juggling many servers needs a single place to fan `reset()`/`pong()` out to the right
+//! connection, to multiplex all of their `Event`s onto one channel, and to query which
+//! connections are healthy, awaiting a pong, or shutting down.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use bitflags::bitflags;
+use futures::future::{self, Either};
+use futures::stream::StreamExt;
+use futures::Future;
+use tokio::sync::mpsc;
+
+use crate::pinger::{Event, Pinger};
+
+/// Servers are identified by name (as registered with the client), mirroring how the rest of the
+/// client refers to connections.
+pub(crate) type ServerId = String;
+
+bitflags! {
+    /// Per-connection health, mirroring the flag-based connection state used in HTTP dispatchers.
+    pub(crate) struct ConnStatus: u8 {
+        /// A healthy connection: pings are being sent and answered.
+        const KEEP_ALIVE       = 0b00001;
+        /// A ping was sent and we're waiting for the server's `PONG`.
+        const AWAITING_PONG    = 0b00010;
+        /// `shutdown()` was called; the pinger task is tearing down.
+        const SHUTDOWN         = 0b00100;
+        /// The pinger gave up on the connection (`Event::Disconnect`) because no `PONG` arrived.
+        const READ_DISCONNECT  = 0b01000;
+        /// The connection was torn down on our end, e.g. by a reconnection routine.
+        const WRITE_DISCONNECT = 0b10000;
+    }
+}
+
+struct Conn {
+    pinger: Pinger,
+}
+
+pub(crate) struct PingerRegistry {
+    conns: Rc<RefCell<HashMap<ServerId, Conn>>>,
+    status: Rc<RefCell<HashMap<ServerId, ConnStatus>>>,
+    snd_ev: mpsc::Sender<(ServerId, Event)>,
+}
+
+impl PingerRegistry {
+    pub(crate) fn new() -> (PingerRegistry, mpsc::Receiver<(ServerId, Event)>) {
+        let (snd_ev, rcv_ev) = mpsc::channel(16);
+        (
+            PingerRegistry {
+                conns: Rc::new(RefCell::new(HashMap::new())),
+                status: Rc::new(RefCell::new(HashMap::new())),
+                snd_ev,
+            },
+            rcv_ev,
+        )
+    }
+
+    /// Spawns a `Pinger` for `serv` and starts forwarding its events onto the shared channel.
+    pub(crate) fn add(&mut self, serv: ServerId) {
+        let (pinger, rcv_ev) = Pinger::new();
+        self.status
+            .borrow_mut()
+            .insert(serv.clone(), ConnStatus::KEEP_ALIVE);
+        self.conns
+            .borrow_mut()
+            .insert(serv.clone(), Conn { pinger });
+
+        let snd_ev = self.snd_ev.clone();
+        let status = self.status.clone();
+        tokio::runtime::current_thread::spawn(forward_events(serv, rcv_ev, snd_ev, status));
+    }
+
+    /// Forwards a "any traffic received" reset to `serv`'s pinger, if it's registered.
+    pub(crate) fn reset(&mut self, serv: &str) {
+        if let Some(conn) = self.conns.borrow_mut().get_mut(serv) {
+            conn.pinger.reset();
+        }
+    }
+
+    /// Forwards a `PONG` to `serv`'s pinger, if it's registered: clears `AWAITING_PONG` and
+    /// confirms `KEEP_ALIVE`.
+    pub(crate) fn pong(&mut self, serv: &str) {
+        if let Some(conn) = self.conns.borrow_mut().get_mut(serv) {
+            conn.pinger.pong();
+        }
+        if let Some(flags) = self.status.borrow_mut().get_mut(serv) {
+            flags.remove(ConnStatus::AWAITING_PONG);
+            flags.insert(ConnStatus::KEEP_ALIVE);
+        }
+    }
+
+    /// Current health flags for `serv`, for e.g. rendering a liveness indicator in its tab.
+    pub(crate) fn status(&self, serv: &str) -> Option<ConnStatus> {
+        self.status.borrow().get(serv).copied()
+    }
+
+    /// Shuts down `serv`'s pinger. Returns a future that resolves once the pinger task has fully
+    /// exited, or immediately if `serv` isn't registered. Once the teardown actually completes,
+    /// `serv`'s `Conn` and status entry are removed entirely -- a torn-down server has nothing
+    /// left to query or forward events for, and keeping the entries around would leak one of
+    /// each per reconnection.
+    pub(crate) fn shutdown(&mut self, serv: &str) -> impl Future<Output = ()> {
+        let serv = serv.to_owned();
+        let conns = self.conns.clone();
+        let status = self.status.clone();
+        if let Some(flags) = status.borrow_mut().get_mut(&serv) {
+            flags.insert(ConnStatus::SHUTDOWN);
+        }
+        let pinger_done = match conns.borrow_mut().get_mut(&serv) {
+            Some(conn) => Either::Left(conn.pinger.shutdown()),
+            None => Either::Right(future::ready(())),
+        };
+        async move {
+            pinger_done.await;
+            conns.borrow_mut().remove(&serv);
+            status.borrow_mut().remove(&serv);
+        }
+    }
+}
+
+async fn forward_events(
+    serv: ServerId,
+    mut rcv_ev: mpsc::Receiver<Event>,
+    mut snd_ev: mpsc::Sender<(ServerId, Event)>,
+    status: Rc<RefCell<HashMap<ServerId, ConnStatus>>>,
+) {
+    while let Some(ev) = rcv_ev.next().await {
+        if let Some(flags) = status.borrow_mut().get_mut(&serv) {
+            match ev {
+                // Still alive, just waiting for the server to confirm it.
+                Event::SendPing => flags.insert(ConnStatus::AWAITING_PONG),
+                // The pinger gave up: no longer alive, and no ping left outstanding.
+                Event::Disconnect => {
+                    flags.remove(ConnStatus::KEEP_ALIVE | ConnStatus::AWAITING_PONG);
+                    flags.insert(ConnStatus::READ_DISCONNECT);
+                }
+            }
+        }
+        if snd_ev.send((serv.clone(), ev)).await.is_err() {
+            return;
+        }
+    }
+}
@@ -3,6 +3,8 @@ pub mod style;
 
 pub mod messaging;
 pub mod msg_area;
+pub mod render;
+pub mod resize;
 pub mod tabbed;
 pub mod termbox;
 pub mod text_field;
@@ -16,11 +18,14 @@ use std::mem;
 use std::str;
 use std::time::Duration;
 
+use futures::{select, stream::StreamExt};
 use rustbox::{RustBox, InitOptions, InputMode, Event, Key};
 use termbox_sys;
 use time::Tm;
 use time;
+use tokio::sync::mpsc;
 
+use self::render::RenderScheduler;
 use self::tabbed::{Tabbed, TabbedRet, MsgSource};
 
 pub struct TUI {
@@ -30,6 +35,15 @@ pub struct TUI {
     /// A tab for every server + channel
     ui       : Tabbed,
 
+    /// Set by the methods that add a message, change a topic or nick list, handle a key or
+    /// resize (see `mark_dirty()`), and cleared once it's actually presented. Lets `draw()` skip the clear+present pair when
+    /// nothing changed since the last call.
+    dirty    : bool,
+
+    /// Throttles how often a `dirty` screen actually triggers a `present()`, so a burst of
+    /// messages coalesces into one repaint instead of one per message.
+    render   : RenderScheduler,
+
     /// For debugging only - `write()` method is called with incomplete lines,
     /// we collect those here. Messages are only shown with `flush()`.
     buffer   : Vec<u8>,
@@ -71,6 +85,8 @@ impl TUI {
         TUI {
             ui: Tabbed::new(tui.width() as i32, tui.height() as i32),
             rustbox: tui,
+            dirty: true,
+            render: RenderScheduler::new(),
             buffer: Vec::with_capacity(100),
             log_file: File::create("logs/debug.txt").unwrap(),
         }
@@ -129,17 +145,24 @@ impl TUI {
             },
 
             Event::ResizeEvent(width, height) => {
-                // This never happens, probably because the our select() loop,
-                // termbox can't really get resize signals.
+                // In practice this never fires: termbox can't deliver resize signals through
+                // our select() loop. Resizes instead arrive via `resize::resize_notifier()`,
+                // which the application should `select!` on and route to `TUI::resize`. We
+                // still handle the event here in case that ever changes.
                 self.resize(width, height);
                 TUIRet::KeyHandled
             },
 
             Event::KeyEvent(key) => {
                 match self.ui.keypressed(key) {
-                    TabbedRet::KeyHandled => TUIRet::KeyHandled,
+                    TabbedRet::KeyHandled => {
+                        // Typing, scrolling, switching tabs etc. all change what's on screen.
+                        self.mark_dirty();
+                        TUIRet::KeyHandled
+                    }
                     TabbedRet::KeyIgnored => TUIRet::KeyIgnored(key),
                     TabbedRet::Input { msg, from } => {
+                        self.mark_dirty();
                         TUIRet::Input {
                             msg: msg,
                             from: from.clone(),
@@ -156,6 +179,7 @@ impl TUI {
 
     pub fn resize(&mut self, width : i32, height : i32) {
         self.ui.resize(width, height);
+        self.mark_dirty();
     }
 
     /// Loop until something's entered to the user input field. Useful for
@@ -172,10 +196,86 @@ impl TUI {
         }
     }
 
-    pub fn draw(&self) {
+    /// Async counterpart of `idle_loop`: `select!`s over keyboard input (fed in by the caller,
+    /// since rustbox's own event reading is blocking) alongside `resize::resize_notifier()` and
+    /// `render::render_ticker()`, so terminal resizes actually reach `TUI::resize` and a burst of
+    /// dirty events left unpresented still gets flushed once it stops.
+    pub async fn event_loop(
+        &mut self,
+        mut input: mpsc::Receiver<Event>,
+        mut resize: mpsc::Receiver<(i32, i32)>,
+        mut render_tick: mpsc::Receiver<()>,
+    ) -> TUIRet {
+        loop {
+            // Throttled, not `draw()`: this is the message-processing loop a burst of
+            // PRIVMSGs/resizes/keypresses runs through, so it should coalesce into the
+            // `RenderScheduler`'s once-per-frame (or once-per-`MAX_BATCH`) cadence instead of
+            // presenting once per iteration. `render_tick` below covers the trailing flush once
+            // a burst stops.
+            self.draw_throttled();
+
+            select! {
+                ev = input.next() => {
+                    match ev {
+                        None => return TUIRet::Abort,
+                        Some(ev) => match self.keypressed(ev) {
+                            ret @ TUIRet::Abort => return ret,
+                            ret @ TUIRet::Input { .. } => return ret,
+                            _ => {}
+                        },
+                    }
+                }
+                size = resize.next() => {
+                    if let Some((width, height)) = size {
+                        self.resize(width, height);
+                    }
+                }
+                tick = render_tick.next() => {
+                    if tick.is_some() {
+                        self.flush_pending();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Marks the screen dirty. Called by the `add_*` message methods, `set_topic`, `add_nick`,
+    /// `remove`, key handling and `resize`; the `new_*_tab` methods do not call it.
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+        self.render.mark_dirty();
+    }
+
+    /// Clears and presents the screen if anything was marked dirty since the last present. Prefer `draw_throttled()` in a loop that
+    /// processes many events per iteration (e.g. a burst of incoming PRIVMSGs), so redraws get
+    /// coalesced instead of happening once per event.
+    pub fn draw(&mut self) {
+        if !self.dirty {
+            return;
+        }
         self.rustbox.clear();
         self.ui.draw(&self.rustbox, 0, 0);
         self.rustbox.present();
+        self.dirty = false;
+        self.render.presented();
+    }
+
+    /// Draws at most once per frame (or once `MAX_BATCH` dirty events have piled up), instead of
+    /// redrawing for every single dirty-marking event. Intended to be called after processing
+    /// each incoming message in a batch, so a flood of traffic amortizes into a handful of
+    /// repaints rather than one `present()` per message.
+    pub fn draw_throttled(&mut self) {
+        if self.render.should_present() {
+            self.draw();
+        }
+    }
+
+    /// Forces a present if anything was left dirty by a burst that stopped before `FRAME`
+    /// elapsed again. Call this on every tick of `render::render_ticker()`.
+    pub fn flush_pending(&mut self) {
+        if self.render.pending() {
+            self.draw();
+        }
     }
 }
 
@@ -207,6 +307,7 @@ impl TUI {
     #[inline]
     pub fn add_client_err_msg(&mut self, msg : &str, target : &MsgTarget) {
         self.ui.add_client_err_msg(msg, target);
+        self.mark_dirty();
     }
 
     /// A message from client, usually just to indidate progress, e.g.
@@ -214,6 +315,7 @@ impl TUI {
     #[inline]
     pub fn add_client_msg(&mut self, msg : &str, target : &MsgTarget) {
         self.ui.add_client_msg(msg, target);
+        self.mark_dirty();
     }
 
     /// privmsg is a message coming from a server or client. Shown with sender's
@@ -221,6 +323,7 @@ impl TUI {
     #[inline]
     pub fn add_privmsg(&mut self, sender : &str, msg : &str, tm : &Tm, target : &MsgTarget) {
         self.ui.add_privmsg(sender, msg, tm, target);
+        self.mark_dirty();
     }
 
     /// A message without any explicit sender info. Useful for e.g. in server
@@ -228,6 +331,7 @@ impl TUI {
     #[inline]
     pub fn add_msg(&mut self, msg : &str, tm : &Tm, target : &MsgTarget) {
         self.ui.add_msg(msg, tm, target);
+        self.mark_dirty();
     }
 
     /// Error messages related with the protocol - e.g. can't join a channel,
@@ -235,20 +339,24 @@ impl TUI {
     #[inline]
     pub fn add_err_msg(&mut self, msg : &str, tm : &Tm, target : &MsgTarget) {
         self.ui.add_err_msg(msg, tm, target);
+        self.mark_dirty();
     }
 
     pub fn set_topic(&mut self, msg : &str, target : &MsgTarget) {
         self.ui.set_topic(msg, target);
+        self.mark_dirty();
     }
 
     #[inline]
     pub fn add_nick(&mut self, nick : &str, target : &MsgTarget) {
         self.ui.add_nick(nick, target);
+        self.mark_dirty();
     }
 
     #[inline]
     pub fn remove(&mut self, nick : &str, target : &MsgTarget) {
         self.ui.remove_nick(nick, target);
+        self.mark_dirty();
     }
 }
 
@@ -0,0 +1,89 @@
+//! Coalesces redraws so a burst of incoming messages (e.g. netsplit/netjoin in a large channel)
+//! doesn't repaint once per message.
+//!
+//! Mirrors the bounded-read / forced-synchronization idea from a PTY event loop: dirty-marking
+//! events are batched and only flushed to a `present()` once per frame, *unless* enough of them
+//! pile up that we force a repaint early so a runaway sender can never starve the UI.
+//!
+//! A burst that stops mid-frame (e.g. an idle connection after a handful of messages) would
+//! otherwise sit unpresented until the next dirty event, possibly forever. `render_ticker()`
+//! gives the caller a `FRAME`-periodic tick to `select!` on so it can force a trailing flush via
+//! `TUI::flush_pending`.
+
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+use tokio::timer::Interval;
+
+/// Minimum time between two `present()` calls.
+const FRAME: Duration = Duration::from_millis(16);
+
+/// Number of dirty-marking events allowed to accumulate before a redraw is forced, even if
+/// `FRAME` hasn't elapsed yet.
+const MAX_BATCH: u32 = 100;
+
+pub(crate) struct RenderScheduler {
+    last_present: Option<Instant>,
+    batched: u32,
+}
+
+impl RenderScheduler {
+    pub(crate) fn new() -> RenderScheduler {
+        RenderScheduler {
+            last_present: None,
+            batched: 0,
+        }
+    }
+
+    /// Call once for every event that marks the UI dirty (a new message, a topic change, ...).
+    pub(crate) fn mark_dirty(&mut self) {
+        self.batched += 1;
+    }
+
+    /// Should we `present()` now? True once the debounce window has elapsed since the last
+    /// `present()`, or once `MAX_BATCH` dirty events have piled up unpresented, whichever comes
+    /// first. False when nothing's dirty.
+    pub(crate) fn should_present(&self) -> bool {
+        if self.batched == 0 {
+            return false;
+        }
+        if self.batched >= MAX_BATCH {
+            return true;
+        }
+        match self.last_present {
+            None => true,
+            Some(last) => last.elapsed() >= FRAME,
+        }
+    }
+
+    /// Is there anything dirty that hasn't been presented yet? Used to drive a trailing flush
+    /// once a burst of dirty events stops, instead of leaving it presented only on the next one.
+    pub(crate) fn pending(&self) -> bool {
+        self.batched > 0
+    }
+
+    /// Call after actually calling `present()`.
+    pub(crate) fn presented(&mut self) {
+        self.last_present = Some(Instant::now());
+        self.batched = 0;
+    }
+}
+
+/// Spawns a task that ticks once per `FRAME`. The caller should `select!` on the returned
+/// receiver alongside input/resize and call `TUI::flush_pending` on every tick, so dirty state
+/// left over after a burst ends still gets presented instead of waiting on the next message.
+pub fn render_ticker() -> mpsc::Receiver<()> {
+    let (snd, rcv) = mpsc::channel(1);
+    tokio::runtime::current_thread::spawn(render_tick_task(snd));
+    rcv
+}
+
+async fn render_tick_task(mut snd: mpsc::Sender<()>) {
+    let mut interval = Interval::new_interval(FRAME);
+    use futures::stream::StreamExt;
+    while interval.next().await.is_some() {
+        // Ignore errors: if the channel's already full a tick is queued, which is all the
+        // caller needs to know to flush.
+        let _ = snd.try_send(());
+    }
+}
@@ -0,0 +1,55 @@
+//! Terminal resize notifications driven by `SIGWINCH`.
+//!
+//! termbox can't deliver resize events through a `select()`-based event loop (see the comment on
+//! `Event::ResizeEvent` in `TUI::keypressed`), so instead we listen for `SIGWINCH` directly and
+//! forward the new terminal size as a message the application can `select!` on alongside input.
+
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+/// Spawns a task that listens for `SIGWINCH` and re-queries the terminal size on every signal.
+/// Returns a receiver the caller can `select!` on; on each message it should call
+/// `TUI::resize(width, height)` followed by a redraw.
+pub fn resize_notifier() -> mpsc::Receiver<(i32, i32)> {
+    let (snd, rcv) = mpsc::channel(1);
+    tokio::runtime::current_thread::spawn(resize_task(snd));
+    rcv
+}
+
+async fn resize_task(mut snd: mpsc::Sender<(i32, i32)>) {
+    let mut sigwinch = match signal(SignalKind::window_change()) {
+        Ok(sig) => sig,
+        Err(_) => return,
+    };
+
+    while sigwinch.recv().await.is_some() {
+        // `termbox_sys::tb_width()`/`tb_height()` only reflect termbox's own cached size, which
+        // it refreshes inside its own (bypassed, here) resize handling -- reading them right
+        // after `SIGWINCH` would hand out the pre-resize dimensions. Ask the tty directly
+        // instead.
+        match terminal_size(libc::STDOUT_FILENO) {
+            Ok(size) => {
+                // Ignore errors: if the channel's already full a resize is queued and about to
+                // be handled, so we'll query the (by-then up to date) size again on the next
+                // `SIGWINCH`.
+                let _ = snd.try_send(size);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Queries the current terminal size via `ioctl(TIOCGWINSZ)`, in `(cols, rows)` order to match
+/// `TUI::resize(width, height)`.
+fn terminal_size(fd: RawFd) -> io::Result<(i32, i32)> {
+    let mut winsz: libc::winsize = unsafe { mem::zeroed() };
+    let ret = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut winsz) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((winsz.ws_col as i32, winsz.ws_row as i32))
+}